@@ -3,16 +3,24 @@
 // License: GNU GPL Version 3 (https://www.gnu.org/licenses/gpl-3.0.en.html)
 
 extern crate byteorder;
+extern crate flate2;
 extern crate png;
 
 #[macro_use]
 extern crate tiff_encoder;
 
-use std::{fmt, fs::File, path::Path};
+use std::collections::HashMap;
+use std::{
+    fmt,
+    fs::File,
+    io::Write,
+    path::Path,
+};
 
 use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::{write::ZlibEncoder, Compression as ZlibCompression};
 use tiff_encoder::ifd::tags;
-use tiff_encoder::ifd::types::BYTE;
+use tiff_encoder::ifd::types::{BYTE, LONG, SRATIONAL};
 use tiff_encoder::prelude::*;
 
 #[cfg(test)]
@@ -55,7 +63,10 @@ impl RgbImage {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<RgbImage, &'static str> {
         let png_file = File::open(path).map_err(|_| "PNG image couldn't be opened.")?;
 
-        let decoder = png::Decoder::new(png_file);
+        let mut decoder = png::Decoder::new(png_file);
+        // Keep EXPAND but drop the default SCALE_16/STRIP_16, which would downsample
+        // 16-bit source images to 8-bit before we ever see their real bit depth.
+        decoder.set_transformations(png::Transformations::EXPAND);
         let (info, mut reader) = decoder
             .read_info()
             .map_err(|_| "This PNG file appears to be corrupted.")?;
@@ -89,138 +100,426 @@ impl RgbImage {
         })
     }
 
-    fn even_width(&self) -> u32 {
-        if self.width % 2 == 0 {
-            self.width
-        } else {
-            self.width - 1
-        }
+    // Crops down to the nearest multiple of the CFA's repeat width.
+    fn even_width(&self, cols: u32) -> u32 {
+        self.width - (self.width % cols)
     }
 
-    fn even_height(&self) -> u32 {
-        if self.height % 2 == 0 {
-            self.height
-        } else {
-            self.height - 1
+    // Crops down to the nearest multiple of the CFA's repeat height.
+    fn even_height(&self, rows: u32) -> u32 {
+        self.height - (self.height % rows)
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        match self.bit_depth {
+            BitDepth::Sixteen => 2,
+            _ => 1,
         }
     }
 
-    fn even_size(&self) -> u32 {
-        self.even_width() * self.even_height()
+    // 16-bit samples are big-endian two-byte pairs; smaller depths are a single byte
+    // left-justified into the result.
+    fn sample_at(&self, sample_index: usize) -> u16 {
+        match self.bit_depth {
+            BitDepth::Sixteen => {
+                let byte_index = sample_index * self.bytes_per_sample();
+                u16::from_be_bytes([self.data[byte_index], self.data[byte_index + 1]])
+            }
+            _ => (self.data[sample_index] as u16) << (16 - self.bit_depth.to_u32()),
+        }
     }
 
-    pub fn to_raw(self, bayer_pattern: BayerPattern) -> RawImage {
-        let width = self.width as usize;
-        let is_even = width % 2 == 0;
-        let color_offsets = bayer_pattern.color_offsets();
-        let shift = 16 - self.bit_depth.to_u32();
+    pub fn to_raw(self, cfa_pattern: CfaPattern) -> RawImage {
+        let out_width = self.even_width(cfa_pattern.cols);
+        let out_height = self.even_height(cfa_pattern.rows);
 
-        let mut raw_data: Vec<u16> = vec![0; self.even_size() as usize];
-        let mut raw_index;
+        // Each dropped column per row desyncs the cropped grid's flat index from the
+        // source's, since the source is strided by the uncropped `self.width`.
+        let dropped_columns = (self.width - out_width) as usize;
 
         let multiplier = match self.color_type {
             ColorType::RGB => 3,
             ColorType::RGBA => 4,
         } as usize;
 
-        for row in (0..self.even_height()).step_by(2) {
-            for column in (0..self.even_width()).step_by(2) {
-                let odd_offset = if is_even { 0 } else { row } as usize;
-
-                // Top Left.
-                raw_index = (row * self.even_width() + column) as usize;
-                raw_data[raw_index] = (self.data
-                    [((raw_index + odd_offset) * multiplier + color_offsets[0] as usize)]
-                    as u16)
-                    << shift;
-
-                // Top Right.
-                raw_index += 1;
-                raw_data[raw_index] = (self.data
-                    [((raw_index + odd_offset) * multiplier + color_offsets[1] as usize)]
-                    as u16)
-                    << shift;
-
-                // Bottom Right.
-                raw_index += self.even_width() as usize;
-                raw_data[raw_index] = (self.data
-                    [((raw_index + odd_offset) * multiplier + color_offsets[3] as usize)]
-                    as u16)
-                    << shift;
-
-                // Bottom Left.
-                raw_index -= 1;
-                raw_data[raw_index] = (self.data
-                    [((raw_index + odd_offset) * multiplier + color_offsets[2] as usize)]
-                    as u16)
-                    << shift;
+        let mut raw_data: Vec<u16> = vec![0; (out_width * out_height) as usize];
+
+        for row in 0..out_height {
+            let odd_offset = row as usize * dropped_columns;
+            for column in 0..out_width {
+                let raw_index = (row * out_width + column) as usize;
+                let channel = cfa_pattern.color_at(row, column) as usize;
+                raw_data[raw_index] =
+                    self.sample_at((raw_index + odd_offset) * multiplier + channel);
             }
         }
 
         RawImage {
-            width: self.even_width(),
-            height: self.even_height(),
+            width: out_width,
+            height: out_height,
             data: raw_data,
-            bayer_pattern: bayer_pattern,
+            cfa_pattern: cfa_pattern,
+            compression: Compression::None,
+            rows_per_strip: out_height,
+            color_matrix1: None,
+            as_shot_neutral: None,
+            as_shot_white_xy: None,
+            black_level: None,
+            white_level: None,
         }
     }
 }
 
-pub enum BayerPattern {
-    RGGB,
-    BGGR,
-    GRBG,
-    GBRG,
+// A repeating rows x cols tile of color indices (0 = red, 1 = green, 2 = blue) that
+// covers the sensor; handles 2x2 Bayer layouts as well as larger mosaics like X-Trans.
+pub struct CfaPattern {
+    rows: u32,
+    cols: u32,
+    indices: Vec<u8>,
 }
-impl fmt::Display for BayerPattern {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                BayerPattern::RGGB => "RGGB",
-                BayerPattern::BGGR => "BGGR",
-                BayerPattern::GRBG => "GRBG",
-                BayerPattern::GBRG => "GBRG",
+
+impl CfaPattern {
+    pub fn rggb() -> CfaPattern {
+        CfaPattern::from_indices(2, 2, vec![0, 1, 1, 2])
+    }
+
+    pub fn bggr() -> CfaPattern {
+        CfaPattern::from_indices(2, 2, vec![2, 1, 1, 0])
+    }
+
+    pub fn grbg() -> CfaPattern {
+        CfaPattern::from_indices(2, 2, vec![1, 0, 2, 1])
+    }
+
+    pub fn gbrg() -> CfaPattern {
+        CfaPattern::from_indices(2, 2, vec![1, 2, 0, 1])
+    }
+
+    // Builds a custom repeating CFA from a flat, row-major vector of color indices.
+    pub fn from_indices(rows: u32, cols: u32, indices: Vec<u8>) -> CfaPattern {
+        assert_eq!(
+            indices.len(),
+            (rows * cols) as usize,
+            "indices must contain exactly rows * cols entries"
+        );
+
+        CfaPattern {
+            rows: rows,
+            cols: cols,
+            indices: indices,
+        }
+    }
+
+    pub fn from_name(cfa_pattern: &str) -> CfaPattern {
+        match cfa_pattern.to_uppercase().trim() {
+            "RGGB" => CfaPattern::rggb(),
+            "BGGR" => CfaPattern::bggr(),
+            "GRBG" => CfaPattern::grbg(),
+            "GBRG" => CfaPattern::gbrg(),
+            _ => panic!("Could not parse CFA pattern from str: Unexpected value given."),
+        }
+    }
+
+    fn color_at(&self, row: u32, column: u32) -> u8 {
+        self.indices[((row % self.rows) * self.cols + (column % self.cols)) as usize]
+    }
+}
+
+// TIFF strip compression scheme applied to the raw image data.
+pub enum Compression {
+    None,
+    PackBits,
+    Lzw,
+    Deflate,
+}
+
+impl Compression {
+    fn tag_value(&self) -> u16 {
+        match self {
+            Compression::None => 1,
+            Compression::Lzw => 5,
+            Compression::Deflate => 8,
+            Compression::PackBits => 32773,
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::PackBits => packbits_encode(data),
+            Compression::Lzw => lzw_encode(data),
+            Compression::Deflate => deflate_encode(data),
+        }
+    }
+}
+
+// TIFF PackBits: a control byte 0..127 means "copy the next n+1 literal bytes", while
+// a negative one (stored as 257-run_length) means "repeat the next byte run_length times".
+fn packbits_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        let mut run = 1;
+        while i + run < data.len() && run < 128 && data[i + run] == data[i] {
+            run += 1;
+        }
+
+        if run >= 2 {
+            out.push((257 - run) as u8);
+            out.push(data[i]);
+            i += run;
+        } else {
+            let start = i;
+            let mut literal_len = 1;
+            i += 1;
+
+            while i < data.len() && literal_len < 128 {
+                let mut next_run = 1;
+                while i + next_run < data.len() && next_run < 128 && data[i + next_run] == data[i]
+                {
+                    next_run += 1;
+                }
+                if next_run >= 2 {
+                    break;
+                }
+                literal_len += 1;
+                i += 1;
             }
-        )
+
+            out.push((literal_len - 1) as u8);
+            out.extend_from_slice(&data[start..start + literal_len]);
+        }
     }
+
+    out
 }
-impl BayerPattern {
-    pub fn from_str(bayer_pattern: &str) -> BayerPattern {
-        match bayer_pattern.to_uppercase().trim() {
-            "RGGB" => BayerPattern::RGGB,
-            "BGGR" => BayerPattern::BGGR,
-            "GRBG" => BayerPattern::GRBG,
-            "GBRG" => BayerPattern::GBRG,
-            _ => panic!("Could not parse Bayer pattern from str: Unexpected value given."),
+
+// Packs codes MSB-first into bytes, zero-padding the final byte.
+struct BitWriter {
+    buffer: Vec<u8>,
+    bit_accumulator: u32,
+    bit_count: u8,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            buffer: Vec::new(),
+            bit_accumulator: 0,
+            bit_count: 0,
+        }
+    }
+
+    fn write_code(&mut self, code: u16, width: u8) {
+        self.bit_accumulator = (self.bit_accumulator << width) | code as u32;
+        self.bit_count += width;
+
+        while self.bit_count >= 8 {
+            self.bit_count -= 8;
+            self.buffer
+                .push(((self.bit_accumulator >> self.bit_count) & 0xFF) as u8);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            let padding = 8 - self.bit_count;
+            self.buffer
+                .push(((self.bit_accumulator << padding) & 0xFF) as u8);
+        }
+        self.buffer
+    }
+}
+
+// TIFF-flavored LZW: codes start at 9 bits and grow up to 12, a leading CLEAR_CODE and
+// trailing EOI_CODE. Decoders lag the encoder's table by one entry (see src/tests.rs), so
+// the width must grow on the code *before* the table overflows the current width.
+fn lzw_encode(data: &[u8]) -> Vec<u8> {
+    const CLEAR_CODE: u16 = 256;
+    const EOI_CODE: u16 = 257;
+    const MAX_CODE_WIDTH: u8 = 12;
+    const MAX_TABLE_SIZE: u16 = 4094;
+
+    fn reset_dictionary() -> (HashMap<Vec<u8>, u16>, u16) {
+        let mut dictionary = HashMap::new();
+        for code in 0..=255u16 {
+            dictionary.insert(vec![code as u8], code);
         }
+        (dictionary, 258)
     }
 
-    fn color_offsets(&self) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    let (mut dictionary, mut next_code) = reset_dictionary();
+    let mut code_width = 9u8;
+
+    writer.write_code(CLEAR_CODE, code_width);
+
+    let mut sequence: Vec<u8> = Vec::new();
+    for &byte in data {
+        let mut candidate = sequence.clone();
+        candidate.push(byte);
+
+        if dictionary.contains_key(&candidate) {
+            sequence = candidate;
+            continue;
+        }
+
+        writer.write_code(dictionary[&sequence], code_width);
+        dictionary.insert(candidate, next_code);
+        next_code += 1;
+
+        if next_code >= (1 << code_width) && code_width < MAX_CODE_WIDTH {
+            code_width += 1;
+        }
+        if next_code >= MAX_TABLE_SIZE {
+            writer.write_code(CLEAR_CODE, code_width);
+            let reset = reset_dictionary();
+            dictionary = reset.0;
+            next_code = reset.1;
+            code_width = 9;
+        }
+
+        sequence = vec![byte];
+    }
+
+    if !sequence.is_empty() {
+        writer.write_code(dictionary[&sequence], code_width);
+    }
+    writer.write_code(EOI_CODE, code_width);
+
+    writer.finish()
+}
+
+// Encodes data as a zlib/Deflate stream.
+fn deflate_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), ZlibCompression::default());
+    encoder
+        .write_all(data)
+        .expect("writing to an in-memory buffer cannot fail");
+    encoder
+        .finish()
+        .expect("finishing an in-memory zlib stream cannot fail")
+}
+
+// A signed rational, stored as (numerator, denominator), matching the SRATIONAL! tag macro.
+pub type Rational = (i32, i32);
+
+const DEFAULT_COLOR_MATRIX1: [Rational; 9] = [
+    (4124564, 10000000),
+    (3575761, 10000000),
+    (1804375, 10000000),
+    (2126729, 10000000),
+    (7151522, 10000000),
+    (721750, 10000000),
+    (193339, 10000000),
+    (1191920, 10000000),
+    (9503041, 10000000),
+];
+const DEFAULT_AS_SHOT_NEUTRAL: [Rational; 3] = [(1, 1), (1, 1), (1, 1)];
+const DEFAULT_AS_SHOT_WHITE_XY: [Rational; 2] = [(1, 1), (1, 1)];
+
+// Error returned by RawImage::save_as_dng.
+#[derive(Debug)]
+pub enum DngError {
+    Io(std::io::Error),
+}
+
+impl fmt::Display for DngError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            BayerPattern::RGGB => vec![0, 1, 1, 2],
-            BayerPattern::BGGR => vec![2, 1, 1, 0],
-            BayerPattern::GRBG => vec![1, 0, 2, 1],
-            BayerPattern::GBRG => vec![1, 2, 0, 1],
+            DngError::Io(err) => write!(f, "failed to write DNG: {}", err),
         }
     }
 }
 
+impl std::error::Error for DngError {}
+
+impl From<std::io::Error> for DngError {
+    fn from(err: std::io::Error) -> Self {
+        DngError::Io(err)
+    }
+}
+
 pub struct RawImage {
     width: u32,
     height: u32,
     data: Vec<u16>,
-    bayer_pattern: BayerPattern,
+    cfa_pattern: CfaPattern,
+    compression: Compression,
+    rows_per_strip: u32,
+    color_matrix1: Option<[Rational; 9]>,
+    as_shot_neutral: Option<[Rational; 3]>,
+    as_shot_white_xy: Option<[Rational; 2]>,
+    black_level: Option<u32>,
+    white_level: Option<u32>,
 }
 
 impl RawImage {
-    pub fn save_as_dng<P: AsRef<Path>>(&self, file_path: P) {
-        // Image bytes
-        let mut image_bytes = Vec::new();
+    // Defaults to Compression::None.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    // Defaults to the whole image in a single strip.
+    pub fn with_rows_per_strip(mut self, rows_per_strip: u32) -> Self {
+        self.rows_per_strip = rows_per_strip.max(1);
+        self
+    }
 
-        for &val in self.data.iter() {
-            image_bytes.write_u16::<LittleEndian>(val).unwrap();
+    // Defaults to a fixed sRGB-derived matrix if never set.
+    pub fn with_color_matrix1(mut self, color_matrix1: [Rational; 9]) -> Self {
+        self.color_matrix1 = Some(color_matrix1);
+        self
+    }
+
+    // Defaults to (1,1,1) if never set.
+    pub fn with_as_shot_neutral(mut self, as_shot_neutral: [Rational; 3]) -> Self {
+        self.as_shot_neutral = Some(as_shot_neutral);
+        self
+    }
+
+    // Defaults to (1,1) if never set.
+    pub fn with_as_shot_white_xy(mut self, as_shot_white_xy: [Rational; 2]) -> Self {
+        self.as_shot_white_xy = Some(as_shot_white_xy);
+        self
+    }
+
+    // Omitted from the DNG if never set.
+    pub fn with_black_level(mut self, black_level: u32) -> Self {
+        self.black_level = Some(black_level);
+        self
+    }
+
+    // Omitted from the DNG if never set.
+    pub fn with_white_level(mut self, white_level: u32) -> Self {
+        self.white_level = Some(white_level);
+        self
+    }
+
+    pub fn save_as_dng<P: AsRef<Path>>(&self, file_path: P) -> Result<(), DngError> {
+        let rows_per_strip = self.rows_per_strip.min(self.height).max(1);
+
+        let mut strip_bytes: Vec<Vec<u8>> = Vec::new();
+        let mut strip_byte_counts: Vec<u32> = Vec::new();
+
+        let mut row = 0;
+        while row < self.height {
+            let rows_in_strip = rows_per_strip.min(self.height - row);
+            let start = (row * self.width) as usize;
+            let end = start + (rows_in_strip * self.width) as usize;
+
+            let mut raw_strip = Vec::with_capacity((end - start) * 2);
+            for &val in &self.data[start..end] {
+                raw_strip.write_u16::<LittleEndian>(val).unwrap();
+            }
+
+            let compressed_strip = self.compression.encode(&raw_strip);
+            strip_byte_counts.push(compressed_strip.len() as u32);
+            strip_bytes.push(compressed_strip);
+
+            row += rows_in_strip;
         }
 
         const TAG_CFAREPEARPATTERNDIM: u16 = 0x828D;
@@ -229,45 +528,111 @@ impl RawImage {
         const TAG_COLORMATRIX1: u16 = 0xC621;
         const TAG_ASSHOTNEUTRAL: u16 = 0xC628;
         const TAG_ASSHOTWHITEXY: u16 = 0xC629;
+        const TAG_BLACKLEVEL: u16 = 0xC61A;
+        const TAG_WHITELEVEL: u16 = 0xC61D;
+
+        let color_matrix1 = self.color_matrix1.unwrap_or(DEFAULT_COLOR_MATRIX1);
+        let as_shot_neutral = self.as_shot_neutral.unwrap_or(DEFAULT_AS_SHOT_NEUTRAL);
+        let as_shot_white_xy = self.as_shot_white_xy.unwrap_or(DEFAULT_AS_SHOT_WHITE_XY);
+
+        let mut ifd = Ifd::new()
+            .with_entry(tags::PhotometricInterpretation, SHORT![32803])
+            .with_entry(tags::NewSubfileType, LONG![0])
+            .with_entry(tags::ImageWidth, LONG![self.width])
+            .with_entry(tags::ImageLength, LONG![self.height])
+            .with_entry(tags::BitsPerSample, SHORT![16])
+            .with_entry(tags::Compression, SHORT![self.compression.tag_value()])
+            .with_entry(tags::Orientation, SHORT![1])
+            .with_entry(tags::SamplesPerPixel, SHORT![1])
+            .with_entry(tags::RowsPerStrip, LONG![rows_per_strip])
+            .with_entry(tags::StripByteCounts, LONG::values(strip_byte_counts))
+            .with_entry(
+                TAG_CFAREPEARPATTERNDIM,
+                SHORT![self.cfa_pattern.rows as u16, self.cfa_pattern.cols as u16],
+            )
+            .with_entry(
+                TAG_CFAPATTERN2,
+                BYTE::values(self.cfa_pattern.indices.clone()),
+            )
+            .with_entry(TAG_DNGVERSION, BYTE![1, 4, 0, 0])
+            .with_entry(TAG_COLORMATRIX1, SRATIONAL::values(color_matrix1))
+            .with_entry(TAG_ASSHOTNEUTRAL, SRATIONAL::values(as_shot_neutral))
+            .with_entry(TAG_ASSHOTWHITEXY, SRATIONAL::values(as_shot_white_xy))
+            .with_entry(tags::StripOffsets, ByteBlock::offsets(strip_bytes));
+
+        if let Some(black_level) = self.black_level {
+            ifd = ifd.with_entry(TAG_BLACKLEVEL, LONG![black_level]);
+        }
+        if let Some(white_level) = self.white_level {
+            ifd = ifd.with_entry(TAG_WHITELEVEL, LONG![white_level]);
+        }
 
-        TiffFile::new(
-            Ifd::new()
-                .with_entry(tags::PhotometricInterpretation, SHORT![32803])
-                .with_entry(tags::NewSubfileType, LONG![0])
-                .with_entry(tags::ImageWidth, LONG![self.width])
-                .with_entry(tags::ImageLength, LONG![self.height])
-                .with_entry(tags::BitsPerSample, SHORT![16])
-                .with_entry(tags::Compression, SHORT![1])
-                .with_entry(tags::Orientation, SHORT![1])
-                .with_entry(tags::SamplesPerPixel, SHORT![1])
-                .with_entry(tags::RowsPerStrip, LONG![self.height])
-                .with_entry(tags::StripByteCounts, LONG![self.width * self.height * 2])
-                .with_entry(TAG_CFAREPEARPATTERNDIM, SHORT![2, 2])
-                .with_entry(
-                    TAG_CFAPATTERN2,
-                    BYTE::values(self.bayer_pattern.color_offsets()),
-                )
-                .with_entry(TAG_DNGVERSION, BYTE![1, 4, 0, 0])
-                .with_entry(
-                    TAG_COLORMATRIX1,
-                    SRATIONAL![
-                        (4124564, 10000000),
-                        (3575761, 10000000),
-                        (1804375, 10000000),
-                        (2126729, 10000000),
-                        (7151522, 10000000),
-                        (0721750, 10000000),
-                        (0193339, 10000000),
-                        (1191920, 10000000),
-                        (9503041, 10000000)
-                    ],
-                )
-                .with_entry(TAG_ASSHOTNEUTRAL, SRATIONAL![(1, 1), (1, 1), (1, 1)])
-                .with_entry(TAG_ASSHOTWHITEXY, SRATIONAL![(1, 1), (1, 1)])
-                .with_entry(tags::StripOffsets, ByteBlock::single(image_bytes))
-                .single(),
-        )
-        .write_to(file_path)
-        .unwrap();
+        TiffFile::new(ifd.single()).write_to(file_path)?;
+        Ok(())
+    }
+
+    fn channel_at(&self, row: u32, column: u32) -> usize {
+        self.cfa_pattern.color_at(row, column) as usize
+    }
+
+    fn sample_at(&self, row: u32, column: u32) -> u16 {
+        self.data[(row * self.width + column) as usize]
+    }
+
+    // Bilinear demosaic: each pixel keeps its own CFA channel verbatim, and the other two
+    // are averaged from same-channel neighbors in the surrounding 3x3 block, clamped at
+    // the image borders.
+    pub fn to_rgb(self) -> RgbImage {
+        let width = self.width;
+        let height = self.height;
+
+        let clamp_replicate = |value: i64, len: u32| -> u32 {
+            value.max(0).min(len as i64 - 1) as u32
+        };
+
+        let mut data = Vec::with_capacity((width * height * 3 * 2) as usize);
+
+        for row in 0..height {
+            for column in 0..width {
+                let own_channel = self.channel_at(row, column);
+
+                let mut sums = [0u32; 3];
+                let mut counts = [0u32; 3];
+                sums[own_channel] = self.sample_at(row, column) as u32;
+                counts[own_channel] = 1;
+
+                for row_offset in -1i64..=1 {
+                    for column_offset in -1i64..=1 {
+                        if row_offset == 0 && column_offset == 0 {
+                            continue;
+                        }
+
+                        let neighbor_row = clamp_replicate(row as i64 + row_offset, height);
+                        let neighbor_column =
+                            clamp_replicate(column as i64 + column_offset, width);
+                        let channel = self.channel_at(neighbor_row, neighbor_column);
+                        if channel == own_channel {
+                            continue;
+                        }
+
+                        sums[channel] += self.sample_at(neighbor_row, neighbor_column) as u32;
+                        counts[channel] += 1;
+                    }
+                }
+
+                for channel in 0..3 {
+                    let value = sums[channel].checked_div(counts[channel]).unwrap_or(0) as u16;
+                    data.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+        }
+
+        RgbImage {
+            width: width,
+            height: height,
+            data: data,
+            color_type: ColorType::RGB,
+            bit_depth: BitDepth::Sixteen,
+        }
     }
 }
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use super::*;
+
+fn packbits_decode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        let control = data[i] as i8;
+        i += 1;
+
+        if control >= 0 {
+            let len = control as usize + 1;
+            out.extend_from_slice(&data[i..i + len]);
+            i += len;
+        } else if control != -128 {
+            let run = 1 - control as isize;
+            let byte = data[i];
+            i += 1;
+            out.extend(std::iter::repeat_n(byte, run as usize));
+        }
+    }
+
+    out
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    byte_pos: usize,
+    bit_pos: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data: data,
+            byte_pos: 0,
+            bit_pos: 0,
+        }
+    }
+
+    fn read_code(&mut self, width: u8) -> Option<u16> {
+        let mut value: u32 = 0;
+
+        for _ in 0..width {
+            if self.byte_pos >= self.data.len() {
+                return None;
+            }
+
+            let bit = (self.data[self.byte_pos] >> (7 - self.bit_pos)) & 1;
+            value = (value << 1) | bit as u32;
+
+            self.bit_pos += 1;
+            if self.bit_pos == 8 {
+                self.bit_pos = 0;
+                self.byte_pos += 1;
+            }
+        }
+
+        Some(value as u16)
+    }
+}
+
+fn lzw_decode(data: &[u8]) -> Vec<u8> {
+    const CLEAR_CODE: u16 = 256;
+    const EOI_CODE: u16 = 257;
+    const MAX_CODE_WIDTH: u8 = 12;
+
+    fn reset_table() -> (HashMap<u16, Vec<u8>>, u16) {
+        let mut table = HashMap::new();
+        for code in 0..=255u16 {
+            table.insert(code, vec![code as u8]);
+        }
+        (table, 258)
+    }
+
+    let mut reader = BitReader::new(data);
+    let mut out = Vec::new();
+    let (mut table, mut next_code) = reset_table();
+    let mut code_width = 9u8;
+    let mut prev: Option<Vec<u8>> = None;
+
+    while let Some(code) = reader.read_code(code_width) {
+        if code == CLEAR_CODE {
+            let reset = reset_table();
+            table = reset.0;
+            next_code = reset.1;
+            code_width = 9;
+            prev = None;
+            continue;
+        }
+        if code == EOI_CODE {
+            break;
+        }
+
+        let entry = match table.get(&code) {
+            Some(existing) => existing.clone(),
+            None => {
+                let mut entry = prev.clone().expect("invalid LZW code sequence");
+                let first = entry[0];
+                entry.push(first);
+                entry
+            }
+        };
+
+        if let Some(previous) = prev {
+            let mut new_entry = previous;
+            new_entry.push(entry[0]);
+            table.insert(next_code, new_entry);
+            next_code += 1;
+            // The decoder trails the encoder's table by one entry (it can't
+            // insert on the first code of a stream), so it must grow the
+            // code width one code earlier to stay in lockstep.
+            if next_code + 1 >= (1 << code_width) && code_width < MAX_CODE_WIDTH {
+                code_width += 1;
+            }
+        }
+
+        out.extend_from_slice(&entry);
+        prev = Some(entry);
+    }
+
+    out
+}
+
+#[test]
+fn packbits_round_trips_runs_and_literals() {
+    let mut data = vec![1, 2, 3, 4, 5];
+    data.extend(std::iter::repeat_n(9u8, 10));
+    data.extend_from_slice(&[7, 8]);
+    data.extend(std::iter::repeat_n(0xAAu8, 130));
+
+    let encoded = packbits_encode(&data);
+    let decoded = packbits_decode(&encoded);
+
+    assert_eq!(decoded, data);
+}
+
+#[test]
+fn lzw_round_trips_ascending_and_modular_data() {
+    let ascending: Vec<u8> = (0..=255u8).collect();
+    let modular: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+
+    for data in [ascending, modular] {
+        let encoded = lzw_encode(&data);
+        let decoded = lzw_decode(&encoded);
+        assert_eq!(decoded, data);
+    }
+}
+
+#[test]
+fn to_rgb_reconstructs_a_flat_image_from_a_bayer_raw() {
+    let raw = RawImage {
+        width: 4,
+        height: 4,
+        data: vec![u16::MAX; 16],
+        cfa_pattern: CfaPattern::rggb(),
+        compression: Compression::None,
+        rows_per_strip: 4,
+        color_matrix1: None,
+        as_shot_neutral: None,
+        as_shot_white_xy: None,
+        black_level: None,
+        white_level: None,
+    };
+
+    let rgb = raw.to_rgb();
+
+    assert_eq!(rgb.width, 4);
+    assert_eq!(rgb.height, 4);
+    assert_eq!(rgb.data.len(), 4 * 4 * 3 * 2);
+    assert!(rgb.data.iter().all(|&byte| byte == 0xFF));
+}